@@ -1,41 +1,355 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
 use std::net::Ipv6Addr;
 use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
 use cidr::Ipv6Cidr;
 use dashmap::DashMap;
 
 use crate::common::PeerId;
 
+/// A single outstanding IPv6 address lease, modeled after a DHCP binding.
+#[derive(Debug, Clone)]
+pub struct Ipv6Lease {
+    pub peer_id: PeerId,
+    pub addr: Ipv6Addr,
+    pub iface: String,
+    pub granted_at: Instant,
+    pub lifetime: Duration,
+}
+
+impl Ipv6Lease {
+    pub fn is_expired(&self) -> bool {
+        self.granted_at.elapsed() > self.lifetime
+    }
+}
+
+#[derive(Debug)]
+struct AllocatorState {
+    // next unit index to hand out if the free-list is empty.
+    next: u128,
+    // unit indices reclaimed from expired/released leases, reused before
+    // `next` is advanced so the pool doesn't grow unbounded under peer churn.
+    free_list: BinaryHeap<Reverse<u128>>,
+}
+
+/// Hands out leases out of `prefix`, one `/unit_prefix_len` unit at a time.
+/// `unit_prefix_len == 128` allocates single host addresses (the classic
+/// NDP-proxy case); a shorter `unit_prefix_len` still longer than `prefix`'s
+/// own carves routed sub-prefixes instead, e.g. a /64 out of a /56 block for
+/// prefix delegation. Either way every peer gets at most one outstanding
+/// lease per allocator, and releasing/expiring it returns the unit's index to
+/// the free-list so a churning set of peers can't exhaust the pool.
 #[derive(Debug)]
 pub struct Ipv6Allocator {
     prefix: Ipv6Cidr,
-    next: Mutex<u128>,
-    assigned: DashMap<PeerId, Ipv6Addr>,
+    unit_prefix_len: u8,
+    state: Mutex<AllocatorState>,
+    leases: DashMap<PeerId, Ipv6Lease>,
 }
 
 impl Ipv6Allocator {
+    /// Allocates single `/128` host addresses out of `prefix`.
     pub fn new(prefix: Ipv6Cidr) -> Self {
+        Self::with_unit_len(prefix, 128)
+    }
+
+    /// Like `new`, but each lease is a `/unit_prefix_len` sub-block rather
+    /// than a single host address.
+    pub fn with_unit_len(prefix: Ipv6Cidr, unit_prefix_len: u8) -> Self {
         Self {
             prefix,
-            next: Mutex::new(1),
-            assigned: DashMap::new(),
+            unit_prefix_len,
+            state: Mutex::new(AllocatorState {
+                next: 1,
+                free_list: BinaryHeap::new(),
+            }),
+            leases: DashMap::new(),
         }
     }
 
-    pub fn allocate(&self, peer_id: PeerId) -> Option<Ipv6Addr> {
-        if let Some(addr) = self.assigned.get(&peer_id) {
-            return Some(*addr);
-        }
-        let host_bits = 128 - self.prefix.network_length() as u8;
-        let max_hosts: u128 = 1u128 << host_bits;
-        let mut idx = self.next.lock().unwrap();
-        if *idx >= max_hosts {
+    pub fn unit_prefix_len(&self) -> u8 {
+        self.unit_prefix_len
+    }
+
+    fn max_units(&self) -> Option<u128> {
+        let unit_bits = (self.unit_prefix_len as i32) - (self.prefix.network_length() as i32);
+        if !(0..=127).contains(&unit_bits) {
             return None;
         }
+        Some(1u128 << unit_bits)
+    }
+
+    /// Allocates (or renews) a lease for `peer_id` with the given `lifetime`.
+    pub fn allocate(
+        &self,
+        peer_id: PeerId,
+        iface: impl Into<String>,
+        lifetime: Duration,
+    ) -> Option<Ipv6Addr> {
+        if let Some(mut lease) = self.leases.get_mut(&peer_id) {
+            lease.granted_at = Instant::now();
+            lease.lifetime = lifetime;
+            return Some(lease.addr);
+        }
+
+        let max_units = self.max_units()?;
+        let idx = {
+            let mut state = self.state.lock().unwrap();
+            if let Some(Reverse(idx)) = state.free_list.pop() {
+                idx
+            } else if state.next < max_units {
+                let idx = state.next;
+                state.next += 1;
+                idx
+            } else {
+                return None;
+            }
+        };
+
         let base: u128 = self.prefix.first_address().into();
-        let addr = Ipv6Addr::from(base + *idx);
-        *idx += 1;
-        self.assigned.insert(peer_id, addr);
+        let unit_bits = 128 - self.unit_prefix_len as u32;
+        let addr = Ipv6Addr::from(base + (idx << unit_bits));
+        self.leases.insert(
+            peer_id,
+            Ipv6Lease {
+                peer_id,
+                addr,
+                iface: iface.into(),
+                granted_at: Instant::now(),
+                lifetime,
+            },
+        );
         Some(addr)
     }
+
+    /// Bumps `granted_at` for an existing lease without reallocating. Returns
+    /// `None` if the peer has no current lease (it must call `allocate` again).
+    pub fn renew(&self, peer_id: PeerId, lifetime: Duration) -> Option<Ipv6Addr> {
+        let mut lease = self.leases.get_mut(&peer_id)?;
+        lease.granted_at = Instant::now();
+        lease.lifetime = lifetime;
+        Some(lease.addr)
+    }
+
+    /// Releases a peer's lease immediately, returning its unit index to the
+    /// free-list so it can be reused by the next `allocate` call.
+    pub fn release(&self, peer_id: PeerId) -> Option<Ipv6Lease> {
+        let (_, lease) = self.leases.remove(&peer_id)?;
+        self.reclaim(&lease.addr);
+        Some(lease)
+    }
+
+    /// Scans all leases and reclaims everything that has expired, returning the
+    /// reclaimed leases so the caller can tear down any associated OS state.
+    pub fn reap_expired(&self) -> Vec<Ipv6Lease> {
+        let expired: Vec<PeerId> = self
+            .leases
+            .iter()
+            .filter(|e| e.value().is_expired())
+            .map(|e| *e.key())
+            .collect();
+
+        let mut reclaimed = Vec::with_capacity(expired.len());
+        for peer_id in expired {
+            if let Some((_, lease)) = self.leases.remove(&peer_id) {
+                self.reclaim(&lease.addr);
+                reclaimed.push(lease);
+            }
+        }
+        reclaimed
+    }
+
+    pub fn lease_of(&self, peer_id: PeerId) -> Option<Ipv6Lease> {
+        self.leases.get(&peer_id).map(|e| e.clone())
+    }
+
+    /// All leases currently outstanding, e.g. so a caller can persist them.
+    pub fn all_leases(&self) -> Vec<Ipv6Lease> {
+        self.leases.iter().map(|e| e.value().clone()).collect()
+    }
+
+    /// Re-installs a lease recovered from disk after a restart, so the unit
+    /// it occupies can never be handed to a different peer while the
+    /// original lease (from before the restart) is still supposed to be
+    /// valid. `lifetime_remaining` should already account for time elapsed
+    /// since the lease was last renewed; a remaining lifetime of zero makes
+    /// the restored lease immediately eligible for `reap_expired`, which
+    /// tears down whatever OS-level state the previous process installed
+    /// for it.
+    pub fn restore(
+        &self,
+        peer_id: PeerId,
+        iface: impl Into<String>,
+        addr: Ipv6Addr,
+        lifetime_remaining: Duration,
+    ) {
+        let base: u128 = self.prefix.first_address().into();
+        let unit_bits = 128 - self.unit_prefix_len as u32;
+        let Some(idx) = u128::from(addr)
+            .checked_sub(base)
+            .map(|offset| offset >> unit_bits)
+        else {
+            return;
+        };
+        {
+            let mut state = self.state.lock().unwrap();
+            if idx >= state.next {
+                state.next = idx + 1;
+            }
+        }
+        self.leases.insert(
+            peer_id,
+            Ipv6Lease {
+                peer_id,
+                addr,
+                iface: iface.into(),
+                granted_at: Instant::now(),
+                lifetime: lifetime_remaining,
+            },
+        );
+    }
+
+    fn reclaim(&self, addr: &Ipv6Addr) {
+        let base: u128 = self.prefix.first_address().into();
+        let unit_bits = 128 - self.unit_prefix_len as u32;
+        let idx = (u128::from(*addr) - base) >> unit_bits;
+        self.state.lock().unwrap().free_list.push(Reverse(idx));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+    use std::thread::sleep;
+
+    use super::*;
+
+    fn prefix(s: &str) -> Ipv6Cidr {
+        Ipv6Cidr::from_str(s).unwrap()
+    }
+
+    #[test]
+    fn allocate_is_sequential_and_idempotent() {
+        let alloc = Ipv6Allocator::new(prefix("fd00::/64"));
+        let a1 = alloc.allocate(1, "eth0", Duration::from_secs(60)).unwrap();
+        let a2 = alloc.allocate(2, "eth0", Duration::from_secs(60)).unwrap();
+        assert_ne!(a1, a2);
+        // Same peer re-requesting gets the same address back, not a new one.
+        let a1_again = alloc.allocate(1, "eth0", Duration::from_secs(60)).unwrap();
+        assert_eq!(a1, a1_again);
+    }
+
+    #[test]
+    fn release_returns_index_to_free_list() {
+        let alloc = Ipv6Allocator::new(prefix("fd00::/64"));
+        let a1 = alloc.allocate(1, "eth0", Duration::from_secs(60)).unwrap();
+        let released = alloc.release(1).unwrap();
+        assert_eq!(released.addr, a1);
+        assert!(alloc.lease_of(1).is_none());
+
+        // A fresh peer reuses the reclaimed index rather than advancing past it.
+        let a2 = alloc.allocate(2, "eth0", Duration::from_secs(60)).unwrap();
+        assert_eq!(a2, a1);
+    }
+
+    #[test]
+    fn reap_expired_reclaims_only_expired_leases() {
+        let alloc = Ipv6Allocator::new(prefix("fd00::/64"));
+        let expired_addr = alloc.allocate(1, "eth0", Duration::from_nanos(1)).unwrap();
+        let live_addr = alloc
+            .allocate(2, "eth0", Duration::from_secs(3600))
+            .unwrap();
+        sleep(Duration::from_millis(5));
+
+        let reaped = alloc.reap_expired();
+        assert_eq!(reaped.len(), 1);
+        assert_eq!(reaped[0].addr, expired_addr);
+        assert!(alloc.lease_of(1).is_none());
+        assert_eq!(alloc.lease_of(2).unwrap().addr, live_addr);
+
+        // The expired peer's index was reclaimed and gets reused.
+        let reused = alloc.allocate(3, "eth0", Duration::from_secs(60)).unwrap();
+        assert_eq!(reused, expired_addr);
+    }
+
+    #[test]
+    fn renew_bumps_lifetime_without_reallocating() {
+        let alloc = Ipv6Allocator::new(prefix("fd00::/64"));
+        let addr = alloc.allocate(1, "eth0", Duration::from_nanos(1)).unwrap();
+        sleep(Duration::from_millis(5));
+        // Renew before the reaper runs: the lease should survive.
+        let renewed = alloc.renew(1, Duration::from_secs(3600)).unwrap();
+        assert_eq!(renewed, addr);
+        assert!(alloc.reap_expired().is_empty());
+        assert_eq!(alloc.lease_of(1).unwrap().addr, addr);
+    }
+
+    #[test]
+    fn renew_unknown_peer_returns_none() {
+        let alloc = Ipv6Allocator::new(prefix("fd00::/64"));
+        assert!(alloc.renew(42, Duration::from_secs(60)).is_none());
+    }
+
+    #[test]
+    fn exhausted_pool_returns_none() {
+        // A /126 has 4 host indices; index 0 is never handed out, so only
+        // 3 host addresses are available.
+        let alloc = Ipv6Allocator::new(prefix("fd00::/126"));
+        for peer_id in 0..3 {
+            assert!(alloc
+                .allocate(peer_id, "eth0", Duration::from_secs(60))
+                .is_some());
+        }
+        assert!(alloc
+            .allocate(999, "eth0", Duration::from_secs(60))
+            .is_none());
+    }
+
+    #[test]
+    fn with_unit_len_carves_sub_prefixes_not_host_addresses() {
+        // A /64 sub-prefix out of a /56 block: 256 possible units.
+        let alloc = Ipv6Allocator::with_unit_len(prefix("fd00::/56"), 64);
+        let first = alloc.allocate(1, "eth0", Duration::from_secs(60)).unwrap();
+        let second = alloc.allocate(2, "eth0", Duration::from_secs(60)).unwrap();
+        assert_eq!(first, Ipv6Addr::from_str("fd00:0:0:1::").unwrap());
+        assert_eq!(second, Ipv6Addr::from_str("fd00:0:0:2::").unwrap());
+    }
+
+    #[test]
+    fn restore_prevents_a_fresh_peer_from_reusing_a_recovered_lease() {
+        // Simulates a process restart: a lease recovered from disk is
+        // restored into a brand-new, otherwise-empty allocator before any
+        // other peer gets to allocate.
+        let alloc = Ipv6Allocator::new(prefix("fd00::/64"));
+        let recovered_addr = Ipv6Addr::from_str("fd00::1").unwrap();
+        alloc.restore(1, "eth0", recovered_addr, Duration::from_secs(3600));
+
+        assert_eq!(alloc.lease_of(1).unwrap().addr, recovered_addr);
+        // A new peer must not collide with the restored lease.
+        let fresh = alloc.allocate(2, "eth0", Duration::from_secs(60)).unwrap();
+        assert_ne!(fresh, recovered_addr);
+    }
+
+    #[test]
+    fn restore_with_zero_remaining_lifetime_is_reaped_immediately() {
+        let alloc = Ipv6Allocator::new(prefix("fd00::/64"));
+        let recovered_addr = Ipv6Addr::from_str("fd00::1").unwrap();
+        alloc.restore(1, "eth0", recovered_addr, Duration::ZERO);
+
+        let reaped = alloc.reap_expired();
+        assert_eq!(reaped.len(), 1);
+        assert_eq!(reaped[0].addr, recovered_addr);
+    }
+
+    #[test]
+    fn all_leases_returns_every_outstanding_lease() {
+        let alloc = Ipv6Allocator::new(prefix("fd00::/64"));
+        alloc.allocate(1, "eth0", Duration::from_secs(60)).unwrap();
+        alloc.allocate(2, "eth1", Duration::from_secs(60)).unwrap();
+        let mut peer_ids: Vec<_> = alloc.all_leases().into_iter().map(|l| l.peer_id).collect();
+        peer_ids.sort();
+        assert_eq!(peer_ids, vec![1, 2]);
+    }
 }
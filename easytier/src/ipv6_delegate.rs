@@ -1,110 +1,401 @@
-use std::collections::HashSet;
+use std::fs;
 use std::net::{IpAddr, Ipv6Addr};
+use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use cidr::Ipv6Inet;
+use cidr::{Ipv6Cidr, Ipv6Inet};
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
 
 use crate::common::error::Error;
 use crate::common::global_ctx::ArcGlobalCtx;
+use crate::common::ipv6_allocator::{Ipv6Allocator, Ipv6Lease};
+use crate::common::PeerId;
 use crate::peers::peer_manager::PeerManager;
+// `Ipv6DelegateRpc`'s `renew_delegation`/`release_delegation` methods, their
+// request/response types, and the `lease_time`/`granted_lifetime`/
+// `server_unix_time`/`requested_prefix_len`/`dns_servers` fields this module
+// reads/writes on them are NOT defined anywhere in this source checkout --
+// the same is true of `config.get_ipv6_delegate_dns_servers()` below. This
+// checkout contains exactly two files (`ipv6_delegate.rs` and
+// `common/ipv6_allocator.rs`); there is no `.proto` file, codegen build
+// script, or config crate here to extend, so no companion schema diff can be
+// produced from within this tree. That is a real gap, not something already
+// handled elsewhere -- the RPC/wire surface this module exposes should not be
+// considered mergeable until the actual schema change lands alongside it.
 use crate::proto::ipv6_delegate::{
-    Ipv6DelegateRpc, RequestDelegationRequest, RequestDelegationResponse,
+    Ipv6DelegateRpc, ReleaseDelegationRequest, ReleaseDelegationResponse, RenewDelegationRequest,
+    RenewDelegationResponse, RequestDelegationRequest, RequestDelegationResponse,
 };
 use crate::proto::rpc_types;
 use crate::proto::rpc_types::controller::BaseController;
-use nix::sys::socket::SockaddrLike;
 
+/// Default lease lifetime handed out when a client doesn't request a specific
+/// one. Clients are expected to renew at roughly half of this.
+const DEFAULT_LEASE_LIFETIME: Duration = Duration::from_secs(3600);
+/// How often the background reaper scans leases for expiry.
+const LEASE_SCAN_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Platform-independent interface enumeration, so prefix/TUN discovery isn't
+/// gated to a single OS. `IfAddrsEnumerator`, used on every platform, backs onto `if-addrs`,
+/// which in turn uses `getifaddrs(3)`/`GetAdaptersAddresses`/equivalents and
+/// yields uniform `(name, address, netmask)` tuples on Linux/macOS/Windows/BSD.
+pub trait OnlinkEnumerator: Send + Sync {
+    fn enumerate(&self) -> Vec<(String, IpAddr, IpAddr)>;
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct IfAddrsEnumerator;
+
+impl OnlinkEnumerator for IfAddrsEnumerator {
+    fn enumerate(&self) -> Vec<(String, IpAddr, IpAddr)> {
+        let Ok(ifaces) = if_addrs::get_if_addrs() else {
+            return Vec::new();
+        };
+        ifaces
+            .into_iter()
+            .map(|iface| match iface.addr {
+                if_addrs::IfAddr::V4(a) => (iface.name, IpAddr::V4(a.ip), IpAddr::V4(a.netmask)),
+                if_addrs::IfAddr::V6(a) => (iface.name, IpAddr::V6(a.ip), IpAddr::V6(a.netmask)),
+            })
+            .collect()
+    }
+}
+
+/// Minimal synchronous netlink(7) client used to install/remove the kernel
+/// state behind a delegation: a /128 route to the tun, a proxy-NDP neighbour
+/// entry on the uplink, the overlay address itself, and source-based policy
+/// rules. Built directly on `netlink-packet-route` instead of shelling out to
+/// iproute2 so failures surface as real `Result`s and every install has a
+/// matching, exact teardown.
+#[cfg(target_os = "linux")]
+mod netlink6 {
+    use std::net::{IpAddr, Ipv6Addr};
+
+    use netlink_packet_core::{
+        NetlinkHeader, NetlinkMessage, NetlinkPayload, NLM_F_ACK, NLM_F_CREATE, NLM_F_EXCL,
+        NLM_F_REPLACE, NLM_F_REQUEST,
+    };
+    use netlink_packet_route::address::{AddressAttribute, AddressMessage, AddressScope};
+    use netlink_packet_route::neighbour::{NeighbourAttribute, NeighbourFlags, NeighbourMessage};
+    use netlink_packet_route::route::{
+        RouteAddress, RouteAttribute, RouteMessage, RouteProtocol, RouteScope, RouteType,
+    };
+    use netlink_packet_route::rule::{RuleAction, RuleAttribute, RuleMessage};
+    use netlink_packet_route::{AddressFamily, RouteNetlinkMessage};
+    use netlink_sys::{protocols::NETLINK_ROUTE, Socket, SocketAddr};
+
+    use crate::common::error::Error;
+
+    fn nl_err(msg: impl Into<String>) -> Error {
+        anyhow::anyhow!(msg.into()).into()
+    }
+
+    /// A single netlink(NETLINK_ROUTE) socket, used request-response style:
+    /// every call sends one `RTM_*` message with `NLM_F_ACK` and blocks for
+    /// the matching ack/error before returning.
+    pub struct Handle {
+        sock: Socket,
+        seq: u32,
+    }
+
+    impl Handle {
+        pub fn new() -> Result<Self, Error> {
+            let mut sock =
+                Socket::new(NETLINK_ROUTE).map_err(|e| nl_err(format!("netlink socket: {e}")))?;
+            sock.bind_auto()
+                .map_err(|e| nl_err(format!("netlink bind: {e}")))?;
+            sock.connect(&SocketAddr::new(0, 0))
+                .map_err(|e| nl_err(format!("netlink connect: {e}")))?;
+            Ok(Self { sock, seq: 0 })
+        }
+
+        pub fn ifindex(&self, name: &str) -> Result<u32, Error> {
+            nix::net::if_::if_nametoindex(name)
+                .map_err(|e| nl_err(format!("if_nametoindex({name}): {e}")))
+        }
+
+        fn request(&mut self, msg: RouteNetlinkMessage, extra_flags: u16) -> Result<(), Error> {
+            self.seq += 1;
+            let mut nl_msg = NetlinkMessage::new(
+                NetlinkHeader {
+                    sequence_number: self.seq,
+                    flags: NLM_F_REQUEST | NLM_F_ACK | extra_flags,
+                    ..Default::default()
+                },
+                NetlinkPayload::from(msg),
+            );
+            nl_msg.finalize();
+            let mut buf = vec![0u8; nl_msg.buffer_len()];
+            nl_msg.serialize(&mut buf);
+            self.sock
+                .send(&buf, 0)
+                .map_err(|e| nl_err(format!("netlink send: {e}")))?;
+
+            let mut recv_buf = vec![0u8; 8192];
+            let n = self
+                .sock
+                .recv(&mut &mut recv_buf[..], 0)
+                .map_err(|e| nl_err(format!("netlink recv: {e}")))?;
+            let resp = NetlinkMessage::<RouteNetlinkMessage>::deserialize(&recv_buf[..n])
+                .map_err(|e| nl_err(format!("netlink decode: {e}")))?;
+            match resp.payload {
+                NetlinkPayload::Error(e) if e.code.is_none() => Ok(()),
+                NetlinkPayload::Error(e) => Err(nl_err(format!("netlink nack: {:?}", e.code))),
+                _ => Ok(()),
+            }
+        }
+
+        /// RTM_NEWROUTE/RTM_DELROUTE: a route for `addr`/`prefix_len` out
+        /// `ifindex`. Used both for single-address (/128) NDP-proxy routes
+        /// and for routed prefix-delegation blocks (prefix_len < 128).
+        pub fn route(
+            &mut self,
+            ifindex: u32,
+            addr: Ipv6Addr,
+            prefix_len: u8,
+            add: bool,
+        ) -> Result<(), Error> {
+            let mut msg = RouteMessage::default();
+            msg.header.address_family = AddressFamily::Inet6;
+            msg.header.destination_prefix_length = prefix_len;
+            msg.header.protocol = RouteProtocol::Boot;
+            msg.header.scope = RouteScope::Universe;
+            msg.header.kind = RouteType::Unicast;
+            msg.attributes
+                .push(RouteAttribute::Destination(RouteAddress::Inet6(addr)));
+            msg.attributes.push(RouteAttribute::Oif(ifindex));
+            if add {
+                self.request(
+                    RouteNetlinkMessage::NewRoute(msg),
+                    NLM_F_CREATE | NLM_F_REPLACE,
+                )
+            } else {
+                self.request(RouteNetlinkMessage::DelRoute(msg), 0)
+            }
+        }
+
+        /// RTM_NEWNEIGH/RTM_DELNEIGH with NTF_PROXY: answer NDP for `addr` on
+        /// `ifindex` on the proxy's behalf.
+        pub fn proxy_neigh(
+            &mut self,
+            ifindex: u32,
+            addr: Ipv6Addr,
+            add: bool,
+        ) -> Result<(), Error> {
+            let mut msg = NeighbourMessage::default();
+            msg.header.family = AddressFamily::Inet6;
+            msg.header.ifindex = ifindex;
+            msg.header.flags = NeighbourFlags::Proxy;
+            msg.attributes
+                .push(NeighbourAttribute::Destination(IpAddr::V6(addr)));
+            if add {
+                self.request(
+                    RouteNetlinkMessage::NewNeighbour(msg),
+                    NLM_F_CREATE | NLM_F_REPLACE,
+                )
+            } else {
+                self.request(RouteNetlinkMessage::DelNeighbour(msg), 0)
+            }
+        }
+
+        /// RTM_NEWADDR/RTM_DELADDR: assign `addr`/`prefixlen` on `ifindex`.
+        pub fn addr(
+            &mut self,
+            ifindex: u32,
+            addr: Ipv6Addr,
+            prefixlen: u8,
+            add: bool,
+        ) -> Result<(), Error> {
+            let mut msg = AddressMessage::default();
+            msg.header.family = AddressFamily::Inet6;
+            msg.header.prefix_len = prefixlen;
+            msg.header.index = ifindex;
+            msg.header.scope = AddressScope::Universe.into();
+            msg.attributes
+                .push(AddressAttribute::Address(IpAddr::V6(addr)));
+            if add {
+                self.request(
+                    RouteNetlinkMessage::NewAddress(msg),
+                    NLM_F_CREATE | NLM_F_REPLACE,
+                )
+            } else {
+                self.request(RouteNetlinkMessage::DelAddress(msg), 0)
+            }
+        }
+
+        /// RTM_NEWRULE/RTM_DELRULE: `from addr/128 lookup table`, for
+        /// source-based policy routing of a delegated address.
+        pub fn rule_from(
+            &mut self,
+            addr: Ipv6Addr,
+            table: u32,
+            priority: u32,
+            add: bool,
+        ) -> Result<(), Error> {
+            let mut msg = RuleMessage::default();
+            msg.header.family = AddressFamily::Inet6;
+            msg.header.src_len = 128;
+            msg.header.action = RuleAction::ToTable;
+            msg.attributes.push(RuleAttribute::Source(IpAddr::V6(addr)));
+            msg.attributes.push(RuleAttribute::Table(table));
+            msg.attributes.push(RuleAttribute::Priority(priority));
+            if add {
+                self.request(RouteNetlinkMessage::NewRule(msg), NLM_F_CREATE | NLM_F_EXCL)
+            } else {
+                self.request(RouteNetlinkMessage::DelRule(msg), 0)
+            }
+        }
+
+        /// RTM_NEWROUTE/RTM_DELROUTE: default route scoped to a policy table,
+        /// so traffic sourced from a delegated address exits via `ifindex`.
+        pub fn default_route(&mut self, ifindex: u32, table: u32, add: bool) -> Result<(), Error> {
+            let mut msg = RouteMessage::default();
+            msg.header.address_family = AddressFamily::Inet6;
+            msg.header.destination_prefix_length = 0;
+            msg.header.protocol = RouteProtocol::Boot;
+            msg.header.scope = RouteScope::Universe;
+            msg.header.kind = RouteType::Unicast;
+            msg.attributes.push(RouteAttribute::Oif(ifindex));
+            msg.attributes.push(RouteAttribute::Table(table));
+            if add {
+                self.request(
+                    RouteNetlinkMessage::NewRoute(msg),
+                    NLM_F_CREATE | NLM_F_REPLACE,
+                )
+            } else {
+                self.request(RouteNetlinkMessage::DelRoute(msg), 0)
+            }
+        }
+    }
+}
+
+/// True for loopback/tunnel/virtual interfaces that should never be treated
+/// as an on-link uplink for delegation, regardless of platform.
+fn is_filtered_ifname(name: &str) -> bool {
+    name.starts_with("lo")
+        || name.starts_with("tun")
+        || name.starts_with("utun")
+        || name.starts_with("wg")
+        || name.starts_with("docker")
+        || name.starts_with("veth")
+        || name.starts_with("br-")
+        || name.starts_with("virbr")
+}
+
+/// The on-link discovery and address/prefix allocation core of the
+/// delegation server, kept separate from `Ipv6DelegateServer` so it can be
+/// exercised with a stub `OnlinkEnumerator` in tests without needing a real
+/// `PeerManager`/`ArcGlobalCtx` (neither of which is constructible from this
+/// source checkout).
 #[derive(Clone)]
-pub struct Ipv6DelegateServer {
-    peer_mgr: Arc<PeerManager>,
-    global_ctx: ArcGlobalCtx,
+struct DelegationAllocator {
+    // One allocator per (on-link block, granted prefix length): a /64 block
+    // handing out /128 NDP-proxy addresses and a /56 block handing out /64
+    // PD sub-prefixes each get their own free-list, so the two modes can
+    // never collide with one another or with themselves. This is the single
+    // source of truth for lease state (address, renew/release, expiry) --
+    // OS-level install/teardown is driven off what it reports, not a second
+    // copy of the same bookkeeping.
+    allocators: Arc<DashMap<(Ipv6Cidr, u8), Arc<Ipv6Allocator>>>,
+    onlink: Arc<dyn OnlinkEnumerator>,
 }
 
-impl Ipv6DelegateServer {
-    pub fn new(peer_mgr: Arc<PeerManager>, global_ctx: ArcGlobalCtx) -> Self {
+impl DelegationAllocator {
+    fn new(onlink: Arc<dyn OnlinkEnumerator>) -> Self {
         Self {
-            peer_mgr,
-            global_ctx,
+            allocators: Arc::new(DashMap::new()),
+            onlink,
         }
     }
 
+    /// Gets (or lazily creates) the allocator handing out `/unit_prefix_len`
+    /// units out of `block`.
+    fn allocator_for(&self, block: Ipv6Cidr, unit_prefix_len: u8) -> Arc<Ipv6Allocator> {
+        self.allocators
+            .entry((block, unit_prefix_len))
+            .or_insert_with(|| Arc::new(Ipv6Allocator::with_unit_len(block, unit_prefix_len)))
+            .clone()
+    }
+
     fn list_onlink_prefixes(&self) -> Vec<(String, Ipv6Inet)> {
-        #[cfg(target_os = "linux")]
-        {
-            let mut ret = Vec::new();
-            // Enumerate interfaces via getifaddrs from netlink ifcfg
-            // We reuse IfConfiger Linux impl: list addresses by name requires a name,
-            // so we iterate getifaddrs directly here.
-            use nix::ifaddrs::getifaddrs;
-            if let Ok(addrs) = getifaddrs() {
-                for iface in addrs {
-                    let name = iface.interface_name;
-                    if name.starts_with("lo")
-                        || name.starts_with("tun")
-                        || name.starts_with("utun")
-                        || name.starts_with("wg")
-                        || name.starts_with("docker")
-                        || name.starts_with("veth")
-                        || name.starts_with("br-")
-                        || name.starts_with("virbr")
-                    {
-                        continue;
-                    }
-                    let (Some(address), Some(netmask)) = (iface.address, iface.netmask) else {
-                        continue;
-                    };
-                    if address.family() == Some(nix::sys::socket::AddressFamily::Inet6)
-                        && netmask.family() == Some(nix::sys::socket::AddressFamily::Inet6)
-                    {
-                        let ip: Ipv6Addr = address.as_sockaddr_in6().unwrap().ip();
-                        let mask: Ipv6Addr = netmask.as_sockaddr_in6().unwrap().ip();
-                        // Only global-scope IPv6
-                        if ip.is_multicast()
-                            || ip.is_loopback()
-                            || ip.is_unspecified()
-                            || ip.is_unique_local()
-                            || ip.is_unicast_link_local()
-                        {
-                            continue;
-                        }
-                        let prefix =
-                            pnet::ipnetwork::ip_mask_to_prefix(IpAddr::V6(mask)).unwrap_or(64);
-                        if prefix != 64 {
-                            continue;
-                        }
-                        if let Ok(inet) = Ipv6Inet::new(ip, 64) {
-                            ret.push((name, inet));
-                        }
-                    }
-                }
+        let mut ret = Vec::new();
+        for (name, addr, netmask) in self.onlink.enumerate() {
+            if is_filtered_ifname(&name) {
+                continue;
+            }
+            let (IpAddr::V6(ip), IpAddr::V6(mask)) = (addr, netmask) else {
+                continue;
+            };
+            // Only global-scope IPv6
+            if ip.is_multicast()
+                || ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_unique_local()
+                || ip.is_unicast_link_local()
+            {
+                continue;
+            }
+            // Accept any routed block of /64 or wider (shorter prefix length):
+            // a /64 is eligible for NDP-proxy delegation, anything shorter can
+            // also be carved into routed sub-prefixes.
+            let prefix = pnet::ipnetwork::ip_mask_to_prefix(IpAddr::V6(mask)).unwrap_or(64);
+            if prefix == 0 || prefix > 64 {
+                continue;
+            }
+            if let Ok(inet) = Ipv6Inet::new(ip, prefix as u8) {
+                ret.push((name, inet));
             }
-            ret
-        }
-        #[cfg(not(target_os = "linux"))]
-        {
-            Vec::new()
         }
+        ret
     }
 
-    fn get_tun_ifname(&self) -> Option<String> {
-        // Heuristic: find the interface that owns our overlay IPv4 address
-        let ipv4 = self.global_ctx.get_ipv4()?.address();
-        use nix::ifaddrs::getifaddrs;
-        for iface in getifaddrs().ok()?.filter(|x| x.address.is_some()) {
-            let addr = iface.address.unwrap();
-            if addr.family() == Some(nix::sys::socket::AddressFamily::Inet) {
-                let ip = addr.as_sockaddr_in().unwrap().ip();
-                if ip == ipv4 {
-                    return Some(iface.interface_name);
-                }
-            }
+    /// Carves a `/requested_prefix_len` sub-prefix out of an on-link block
+    /// that is shorter than /64 (e.g. a /56). Allocation goes through the
+    /// block's `Ipv6Allocator`, so repeated requests from the same peer are
+    /// idempotent (its existing lease is returned) and two different peers
+    /// can never be handed the same sub-prefix while both leases are live.
+    /// Returns `None` when no such block is configured, the request doesn't
+    /// make sense (e.g. asking for a /128, which is the NDP-proxy path
+    /// instead), or the block is exhausted.
+    fn alloc_prefix_for_peer(
+        &self,
+        requester_peer_id: u32,
+        requested_prefix_len: u8,
+        lifetime: Duration,
+    ) -> Option<(String, Ipv6Inet)> {
+        if requested_prefix_len == 0 || requested_prefix_len >= 128 {
+            return None;
         }
-        None
+        let (iface, block) = self.list_onlink_prefixes().into_iter().find(|(_, pfx)| {
+            pfx.network_length() < 64 && pfx.network_length() < requested_prefix_len
+        })?;
+
+        let cidr_block = Ipv6Cidr::new(block.first_address(), block.network_length()).ok()?;
+        let allocator = self.allocator_for(cidr_block, requested_prefix_len);
+        let sub_addr = allocator.allocate(requester_peer_id, iface.clone(), lifetime)?;
+        Ipv6Inet::new(sub_addr, requested_prefix_len)
+            .ok()
+            .map(|sub| (iface, sub))
     }
 
-    fn alloc_addrs_for_peer(&self, requester_peer_id: u32, count: u32) -> Vec<(String, Ipv6Inet)> {
-        // one per on-link /64 by default
-        let prefixes = self.list_onlink_prefixes();
+    /// One `/128` (NDP-proxy) lease per shared on-link `/64` by default;
+    /// shorter blocks are handled by the prefix-delegation path instead.
+    /// Allocation goes through each `/64`'s `Ipv6Allocator`, which is also
+    /// what the lease reaper and `renew_delegation`/`release_delegation`
+    /// operate on, so address issuance and lease lifecycle can't drift apart.
+    fn alloc_addrs_for_peer(
+        &self,
+        requester_peer_id: u32,
+        count: u32,
+        lifetime: Duration,
+    ) -> Vec<(String, Ipv6Inet)> {
+        let prefixes: Vec<_> = self
+            .list_onlink_prefixes()
+            .into_iter()
+            .filter(|(_, pfx)| pfx.network_length() == 64)
+            .collect();
         if prefixes.is_empty() {
             return vec![];
         }
@@ -115,43 +406,307 @@ impl Ipv6DelegateServer {
         } as usize;
 
         let mut out = Vec::new();
-        let mut used_iids: HashSet<u64> = HashSet::new();
-        for (idx, (iface, pfx)) in prefixes.into_iter().enumerate() {
-            if idx >= want {
-                break;
+        for (iface, pfx) in prefixes.into_iter().take(want) {
+            let Ok(cidr_block) = Ipv6Cidr::new(pfx.first_address(), pfx.network_length()) else {
+                continue;
+            };
+            let allocator = self.allocator_for(cidr_block, 128);
+            let Some(addr) = allocator.allocate(requester_peer_id, iface.clone(), lifetime) else {
+                continue;
+            };
+            out.push((iface, Ipv6Inet::new(addr, 128).unwrap()));
+        }
+        out
+    }
+}
+
+/// A single lease snapshotted to disk, keyed by which block/granularity
+/// allocator it came from (so it can be restored into the right
+/// `Ipv6Allocator` after a restart) and with an absolute expiry instead of
+/// `Ipv6Lease`'s process-local `Instant`, which can't survive a restart.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct PersistedLease {
+    peer_id: PeerId,
+    block: String,
+    unit_prefix_len: u8,
+    addr: Ipv6Addr,
+    iface: String,
+    expires_at_unix: u64,
+}
+
+/// Where lease state survives a server restart (upgrade, config reload,
+/// crash/respawn). Without this, a freshly-started process would reset every
+/// allocator to an empty free-list while the kernel still has the previous
+/// process's routes/proxy-neigh/policy state installed for leases that may
+/// still be well within their renewal window, and the very next allocation
+/// could hand a different peer that same address/prefix.
+fn lease_file_path() -> PathBuf {
+    std::env::var_os("EASYTIER_IPV6_DELEGATE_LEASE_FILE")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from("/var/lib/easytier/ipv6_delegate_leases.json"))
+}
+
+fn load_persisted_leases() -> Vec<PersistedLease> {
+    let Ok(contents) = fs::read_to_string(lease_file_path()) else {
+        return Vec::new();
+    };
+    serde_json::from_str(&contents).unwrap_or_default()
+}
+
+fn save_persisted_leases(leases: &[PersistedLease]) {
+    let path = lease_file_path();
+    if let Some(parent) = path.parent() {
+        let _ = fs::create_dir_all(parent);
+    }
+    match serde_json::to_string_pretty(leases) {
+        Ok(json) => {
+            if let Err(e) = fs::write(&path, json) {
+                tracing::warn!(?e, path = %path.display(), "failed to persist ipv6 delegate leases");
             }
-            // deterministic IID based on (peer_id, prefix)
-            use sha2::{Digest, Sha256};
-            let mut hasher = Sha256::new();
-            hasher.update(requester_peer_id.to_be_bytes());
-            hasher.update(pfx.first_address().octets());
-            let digest = hasher.finalize();
-            let mut iid_bytes = [0u8; 8];
-            iid_bytes.copy_from_slice(&digest[0..8]);
-            let mut iid = u64::from_be_bytes(iid_bytes);
-            iid |= 1; // avoid :: as iid
-                      // avoid duplicates just in case
-            let mut salt = 0u64;
-            while used_iids.contains(&iid) {
-                iid = iid.wrapping_add(1 + salt);
-                salt = salt.wrapping_add(1);
+        }
+        Err(e) => {
+            tracing::warn!(?e, "failed to serialize ipv6 delegate leases");
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Ipv6DelegateServer {
+    peer_mgr: Arc<PeerManager>,
+    global_ctx: ArcGlobalCtx,
+    alloc: DelegationAllocator,
+    // The /128 NDP-proxy mode also mirrors the peer's address onto our own
+    // tun (see `request_delegation`) so the server itself has a usable
+    // overlay address; this tracks which address that was per peer so it
+    // gets a matching RTM_DELADDR when the lease expires or is released,
+    // instead of leaking one address per peer forever.
+    server_overlay_addrs: Arc<DashMap<PeerId, Ipv6Addr>>,
+}
+
+impl Ipv6DelegateServer {
+    pub fn new(peer_mgr: Arc<PeerManager>, global_ctx: ArcGlobalCtx) -> Self {
+        let ret = Self {
+            peer_mgr,
+            global_ctx,
+            alloc: DelegationAllocator::new(Arc::new(IfAddrsEnumerator)),
+            server_overlay_addrs: Arc::new(DashMap::new()),
+        };
+        ret.restore_persisted_leases();
+        ret.spawn_lease_reaper();
+        ret
+    }
+
+    /// Loads whatever lease file a previous instance of this process left
+    /// behind and re-seeds the relevant allocators with it, so a restart
+    /// can't hand a still-valid peer's address/prefix to someone else. Any
+    /// lease whose remaining lifetime already hit zero is left to the
+    /// regular reaper to tear down, which also cleans up the stale kernel
+    /// state the previous process installed for it.
+    fn restore_persisted_leases(&self) {
+        let now = Self::now_unix();
+        for entry in load_persisted_leases() {
+            let Ok(block) = entry.block.parse::<Ipv6Cidr>() else {
+                continue;
+            };
+            let allocator = self.alloc.allocator_for(block, entry.unit_prefix_len);
+            let remaining = Duration::from_secs(entry.expires_at_unix.saturating_sub(now));
+            allocator.restore(entry.peer_id, entry.iface, entry.addr, remaining);
+        }
+    }
+
+    /// Snapshots every outstanding lease across every allocator to disk.
+    /// Called after every allocate/renew/release and on every reaper scan so
+    /// the on-disk state never drifts far from the in-memory truth.
+    fn persist_leases(allocators: &DashMap<(Ipv6Cidr, u8), Arc<Ipv6Allocator>>) {
+        let now = Self::now_unix();
+        let snapshot: Vec<PersistedLease> = allocators
+            .iter()
+            .flat_map(|entry| {
+                let (block, unit_prefix_len) = *entry.key();
+                entry.value().all_leases().into_iter().map(move |lease| {
+                    let remaining = lease.lifetime.saturating_sub(lease.granted_at.elapsed());
+                    PersistedLease {
+                        peer_id: lease.peer_id,
+                        block: block.to_string(),
+                        unit_prefix_len,
+                        addr: lease.addr,
+                        iface: lease.iface,
+                        expires_at_unix: now + remaining.as_secs(),
+                    }
+                })
+            })
+            .collect();
+        save_persisted_leases(&snapshot);
+    }
+
+    /// Periodically scans every allocator for expired leases and tears down
+    /// whatever OS-level state was installed for them, so a churning set of
+    /// peers can't leak routes/proxy entries/policy rules forever.
+    fn spawn_lease_reaper(&self) {
+        let allocators = self.alloc.allocators.clone();
+        let global_ctx = self.global_ctx.clone();
+        let onlink = self.alloc.onlink.clone();
+        let server_overlay_addrs = self.server_overlay_addrs.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(LEASE_SCAN_INTERVAL).await;
+                // Always reclaim expired addresses/prefixes back to the
+                // free-list even if the tun can't be resolved right now --
+                // only the OS-level teardown needs it.
+                let tun = Self::resolve_tun_ifname(&global_ctx, &onlink);
+                for entry in allocators.iter() {
+                    let (_, unit_prefix_len) = *entry.key();
+                    for lease in entry.value().reap_expired() {
+                        Self::reclaim_lease(
+                            &global_ctx,
+                            &onlink,
+                            &server_overlay_addrs,
+                            tun.as_deref(),
+                            &lease,
+                            unit_prefix_len,
+                        )
+                        .await;
+                    }
+                }
+                Self::persist_leases(&allocators);
             }
-            used_iids.insert(iid);
-
-            let mut segs = pfx.first_address().segments();
-            let hi = ((iid >> 48) & 0xFFFF) as u16;
-            let h2 = ((iid >> 32) & 0xFFFF) as u16;
-            let h3 = ((iid >> 16) & 0xFFFF) as u16;
-            let lo = (iid & 0xFFFF) as u16;
-            segs[4] = hi;
-            segs[5] = h2;
-            segs[6] = h3;
-            segs[7] = lo;
-            let addr = Ipv6Addr::from(segs);
-            let inet = Ipv6Inet::new(addr, 128).unwrap();
-            out.push((iface, inet));
+        });
+    }
+
+    /// Reclaims a single lease that's being expired/released: tears down
+    /// whatever OS-level state was installed for it, and -- if its address
+    /// was also mirrored onto our own tun as the server's overlay address --
+    /// removes that mirrored address too so it doesn't leak.
+    async fn reclaim_lease(
+        global_ctx: &ArcGlobalCtx,
+        onlink: &Arc<dyn OnlinkEnumerator>,
+        server_overlay_addrs: &DashMap<PeerId, Ipv6Addr>,
+        tun: Option<&str>,
+        lease: &Ipv6Lease,
+        unit_prefix_len: u8,
+    ) {
+        if let Some(tun) = tun {
+            Self::teardown_allocation(
+                global_ctx,
+                onlink,
+                &lease.iface,
+                tun,
+                lease.addr,
+                unit_prefix_len,
+            )
+            .await;
+        }
+        if unit_prefix_len != 128 {
+            return;
+        }
+        let was_overlay = server_overlay_addrs
+            .get(&lease.peer_id)
+            .map(|addr| *addr == lease.addr)
+            .unwrap_or(false);
+        if !was_overlay {
+            return;
+        }
+        server_overlay_addrs.remove(&lease.peer_id);
+        if let Some(tun) = tun {
+            Self::teardown_server_overlay_addr(tun, lease.addr).await;
         }
-        out
+    }
+
+    /// Tears down exactly what `install_ndp_proxy_and_route`/
+    /// `install_routed_prefix` installed for a single reclaimed lease,
+    /// including the source-policy rule for the /128 NDP-proxy case.
+    #[cfg(target_os = "linux")]
+    async fn teardown_allocation(
+        global_ctx: &ArcGlobalCtx,
+        onlink: &Arc<dyn OnlinkEnumerator>,
+        iface: &str,
+        tun: &str,
+        addr: Ipv6Addr,
+        unit_prefix_len: u8,
+    ) {
+        // A /128 was installed via NDP-proxy; anything wider is a routed
+        // prefix-delegation block with no proxy-neigh entry to remove.
+        let result = if unit_prefix_len == 128 {
+            Self::teardown_ndp_proxy_and_route(iface, tun, addr)
+        } else {
+            Ipv6Inet::new(addr, unit_prefix_len)
+                .map_err(|e| {
+                    anyhow::anyhow!("invalid delegated prefix {addr}/{unit_prefix_len}: {e}").into()
+                })
+                .and_then(|inet| Self::teardown_routed_prefix(tun, &inet))
+        };
+        if let Err(e) = result {
+            tracing::warn!(?e, iface, tun, %addr, "failed to tear down expired ipv6 delegation");
+        }
+        if unit_prefix_len == 128 {
+            if let Err(e) = teardown_source_policy_for_addr(global_ctx, onlink, addr).await {
+                tracing::warn!(?e, %addr, "failed to tear down ipv6 delegation source policy");
+            }
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    async fn teardown_allocation(
+        _global_ctx: &ArcGlobalCtx,
+        _onlink: &Arc<dyn OnlinkEnumerator>,
+        _iface: &str,
+        _tun: &str,
+        _addr: Ipv6Addr,
+        _unit_prefix_len: u8,
+    ) {
+    }
+
+    fn get_tun_ifname(&self) -> Option<String> {
+        Self::resolve_tun_ifname(&self.global_ctx, &self.alloc.onlink)
+    }
+
+    /// Heuristic: find the interface that owns our overlay IPv4 address.
+    /// Standalone so the background lease reaper can call it without holding
+    /// a `&self`.
+    fn resolve_tun_ifname(
+        global_ctx: &ArcGlobalCtx,
+        onlink: &Arc<dyn OnlinkEnumerator>,
+    ) -> Option<String> {
+        let ipv4 = global_ctx.get_ipv4()?.address();
+        onlink
+            .enumerate()
+            .into_iter()
+            .find_map(|(name, addr, _)| match addr {
+                IpAddr::V4(ip) if ip == ipv4 => Some(name),
+                _ => None,
+            })
+    }
+
+    /// Resolvers to hand out alongside a delegation, mirroring a DHCP
+    /// server's DNS option: an explicit config override takes priority,
+    /// otherwise fall back to whatever IPv6 resolvers this node itself uses.
+    fn resolve_dns_servers(&self) -> Vec<Ipv6Addr> {
+        let configured = self.global_ctx.config.get_ipv6_delegate_dns_servers();
+        if !configured.is_empty() {
+            return configured;
+        }
+        Self::parse_resolv_conf_ipv6()
+    }
+
+    #[cfg(target_os = "linux")]
+    fn parse_resolv_conf_ipv6() -> Vec<Ipv6Addr> {
+        let Ok(contents) = std::fs::read_to_string("/etc/resolv.conf") else {
+            return Vec::new();
+        };
+        contents
+            .lines()
+            .filter_map(|line| {
+                let mut words = line.split_whitespace();
+                (words.next()? == "nameserver").then_some(())?;
+                words.next()?.parse::<Ipv6Addr>().ok()
+            })
+            .filter(|addr| !addr.is_loopback() && !addr.is_unicast_link_local())
+            .collect()
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn parse_resolv_conf_ipv6() -> Vec<Ipv6Addr> {
+        Vec::new()
     }
 
     #[cfg(target_os = "linux")]
@@ -160,20 +715,113 @@ impl Ipv6DelegateServer {
     }
 
     #[cfg(target_os = "linux")]
-    fn install_ndp_proxy_and_route(&self, iface: &str, tun: &str, addr: Ipv6Addr) {
-        // Enable forwarding and proxy_ndp
+    fn install_ndp_proxy_and_route(
+        &self,
+        iface: &str,
+        tun: &str,
+        addr: Ipv6Addr,
+    ) -> Result<(), Error> {
+        // Enable forwarding and proxy_ndp; these are global knobs, not
+        // per-route kernel state, so they aren't part of the netlink teardown.
         self.enable_sysctl("net/ipv6/conf/all/forwarding", "1");
         self.enable_sysctl(&format!("net/ipv6/conf/{iface}/proxy_ndp"), "1");
-        // ip -6 route add <addr>/128 dev <tun>
-        let _ = std::process::Command::new("sh")
-            .arg("-c")
-            .arg(format!("ip -6 route replace {}/128 dev {}", addr, tun))
-            .status();
-        // ip -6 neigh add proxy <addr> dev <iface>
-        let _ = std::process::Command::new("sh")
-            .arg("-c")
-            .arg(format!("ip -6 neigh replace proxy {} dev {}", addr, iface))
-            .status();
+
+        let mut nl = netlink6::Handle::new()?;
+        let tun_idx = nl.ifindex(tun)?;
+        let iface_idx = nl.ifindex(iface)?;
+        nl.route(tun_idx, addr, 128, true)?;
+        nl.proxy_neigh(iface_idx, addr, true)?;
+        Ok(())
+    }
+
+    #[cfg(target_os = "linux")]
+    fn teardown_ndp_proxy_and_route(iface: &str, tun: &str, addr: Ipv6Addr) -> Result<(), Error> {
+        let mut nl = netlink6::Handle::new()?;
+        let tun_idx = nl.ifindex(tun)?;
+        let iface_idx = nl.ifindex(iface)?;
+        nl.route(tun_idx, addr, 128, false)?;
+        nl.proxy_neigh(iface_idx, addr, false)?;
+        Ok(())
+    }
+
+    /// Prefix-delegation mode: route the whole sub-prefix at the peer's tun
+    /// instead of proxying NDP for a single address. No proxy-neigh entry is
+    /// needed since the peer re-announces the prefix to its own downstream.
+    #[cfg(target_os = "linux")]
+    fn install_routed_prefix(tun: &str, prefix: &Ipv6Inet) -> Result<(), Error> {
+        let mut nl = netlink6::Handle::new()?;
+        let tun_idx = nl.ifindex(tun)?;
+        nl.route(
+            tun_idx,
+            prefix.first_address(),
+            prefix.network_length(),
+            true,
+        )
+    }
+
+    #[cfg(target_os = "linux")]
+    fn teardown_routed_prefix(tun: &str, prefix: &Ipv6Inet) -> Result<(), Error> {
+        let mut nl = netlink6::Handle::new()?;
+        let tun_idx = nl.ifindex(tun)?;
+        nl.route(
+            tun_idx,
+            prefix.first_address(),
+            prefix.network_length(),
+            false,
+        )
+    }
+
+    /// Reverses the `nl.addr(tun_idx, overlay, 128, true)` call in
+    /// `request_delegation` that mirrors a peer's /128 onto our own tun.
+    #[cfg(target_os = "linux")]
+    async fn teardown_server_overlay_addr(tun: &str, addr: Ipv6Addr) {
+        let result = (|| -> Result<(), Error> {
+            let mut nl = netlink6::Handle::new()?;
+            let tun_idx = nl.ifindex(tun)?;
+            nl.addr(tun_idx, addr, 128, false)
+        })();
+        if let Err(e) = result {
+            tracing::warn!(?e, tun, %addr, "failed to remove server overlay ipv6 addr");
+        }
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    async fn teardown_server_overlay_addr(_tun: &str, _addr: Ipv6Addr) {}
+
+    /// Applies delegated DNS resolvers to the overlay interface. Resolver
+    /// configuration has no netlink RTM_* equivalent (it's not kernel route/
+    /// neighbor/address state), so unlike the rest of this module this still
+    /// shells out, to `resolvectl` (systemd-resolved); other resolver
+    /// managers are not handled.
+    #[cfg(target_os = "linux")]
+    fn apply_dns_servers_to_tun(tun: &str, dns_servers: &[Ipv6Addr]) -> Result<(), Error> {
+        let addrs: Vec<String> = dns_servers.iter().map(|a| a.to_string()).collect();
+        let output = std::process::Command::new("resolvectl")
+            .arg("dns")
+            .arg(tun)
+            .args(&addrs)
+            .output()
+            .map_err(|e| anyhow::anyhow!("resolvectl spawn failed: {e}"))?;
+        if !output.status.success() {
+            return Err(anyhow::anyhow!(
+                "resolvectl dns {tun} failed: {}",
+                String::from_utf8_lossy(&output.stderr)
+            )
+            .into());
+        }
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn apply_dns_servers_to_tun(_tun: &str, _dns_servers: &[Ipv6Addr]) -> Result<(), Error> {
+        Ok(())
+    }
+
+    fn now_unix() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs()
     }
 }
 
@@ -196,98 +844,316 @@ impl Ipv6DelegateRpc for Ipv6DelegateServer {
                 addrs: vec![],
                 error: "server disabled".to_string(),
                 server_overlay_ipv6: None,
+                granted_lifetime: 0,
+                server_unix_time: Self::now_unix(),
+                dns_servers: vec![],
             });
         }
-        let addrs = self.alloc_addrs_for_peer(request.requester_peer_id, request.count);
+        // Prefer handing out a routed sub-prefix (IA_PD style) when the peer
+        // asked for one and a delegatable block shorter than /64 exists;
+        // otherwise fall back to the classic one-proxied-/128-per-/64 mode.
+        let lifetime = if request.lease_time == 0 {
+            DEFAULT_LEASE_LIFETIME
+        } else {
+            Duration::from_secs(request.lease_time as u64)
+        };
+        let (addrs, pd_mode) = match self.alloc.alloc_prefix_for_peer(
+            request.requester_peer_id,
+            request.requested_prefix_len as u8,
+            lifetime,
+        ) {
+            Some(prefix) => (vec![prefix], true),
+            None => (
+                self.alloc
+                    .alloc_addrs_for_peer(request.requester_peer_id, request.count, lifetime),
+                false,
+            ),
+        };
         if addrs.is_empty() {
             return Ok(RequestDelegationResponse {
                 addrs: vec![],
                 error: "no on-link /64 found".to_string(),
                 server_overlay_ipv6: None,
+                granted_lifetime: 0,
+                server_unix_time: Self::now_unix(),
+                dns_servers: vec![],
             });
         }
-        // Apply OS-level proxying (Linux only)
+        let tun = self.get_tun_ifname();
+        // Apply OS-level proxying/routing (Linux only). Failures are
+        // collected rather than ignored so the caller can tell a partial
+        // delegation apart from a fully-working one.
+        let mut install_errors: Vec<String> = Vec::new();
         #[cfg(target_os = "linux")]
-        if let Some(tun) = self.get_tun_ifname() {
+        if let Some(tun) = &tun {
             for (iface, inet) in &addrs {
-                self.install_ndp_proxy_and_route(iface, &tun, inet.address());
+                let result = if pd_mode {
+                    Self::install_routed_prefix(tun, inet)
+                } else {
+                    self.install_ndp_proxy_and_route(iface, tun, inet.address())
+                };
+                if let Err(e) = result {
+                    install_errors.push(format!("{iface}/{inet}: {e}"));
+                }
             }
         }
         // Ensure server also has an overlay IPv6 so clients can set exit-node
-        let server_overlay_ipv6 = addrs.first().map(|(_, inet)| inet.address());
-        // Use a leading underscore to avoid unused-variable warning on non-Linux targets
-        if let Some(_overlay) = server_overlay_ipv6 {
-            // assign to tun if possible (shell out on Linux)
-            #[cfg(target_os = "linux")]
-            if let Some(tun) = self.get_tun_ifname() {
-                let _ = std::process::Command::new("sh")
-                    .arg("-c")
-                    .arg(format!("ip -6 addr replace {}/128 dev {}", _overlay, tun))
-                    .status();
+        // A delegated prefix belongs entirely to the peer; only a /128 NDP-
+        // proxy grant also gives the server itself a usable overlay address.
+        let server_overlay_ipv6 = (!pd_mode)
+            .then(|| addrs.first().map(|(_, inet)| inet.address()))
+            .flatten();
+        #[cfg(target_os = "linux")]
+        if let (Some(overlay), Some(tun)) = (server_overlay_ipv6, &tun) {
+            let result = (|| -> Result<(), Error> {
+                let mut nl = netlink6::Handle::new()?;
+                let tun_idx = nl.ifindex(tun)?;
+                nl.addr(tun_idx, overlay, 128, true)
+            })();
+            if let Err(e) = result {
+                install_errors.push(format!("server overlay addr {overlay}: {e}"));
+            } else {
+                self.server_overlay_addrs
+                    .insert(request.requester_peer_id, overlay);
+                if let Err(e) =
+                    configure_source_policy_for_addr(&self.global_ctx, &self.alloc.onlink, overlay)
+                        .await
+                {
+                    install_errors.push(format!("server overlay addr {overlay} policy: {e}"));
+                }
             }
         }
+
+        let dns_servers = self.resolve_dns_servers();
+        #[cfg(target_os = "linux")]
+        if let Some(tun) = &tun {
+            if !dns_servers.is_empty() {
+                if let Err(e) = Self::apply_dns_servers_to_tun(tun, &dns_servers) {
+                    install_errors.push(format!("dns servers {tun}: {e}"));
+                }
+            }
+        }
+
+        Self::persist_leases(&self.alloc.allocators);
+
         let resp = RequestDelegationResponse {
             addrs: addrs.into_iter().map(|(_, inet)| inet.into()).collect(),
-            error: String::new(),
+            error: install_errors.join("; "),
             server_overlay_ipv6: server_overlay_ipv6.map(Into::into),
+            granted_lifetime: lifetime.as_secs() as u32,
+            server_unix_time: Self::now_unix(),
+            dns_servers: dns_servers.into_iter().map(Into::into).collect(),
         };
         Ok(resp)
     }
+
+    /// Bumps the lease for a peer that already holds a delegation, without
+    /// reallocating or touching the installed routes/proxy entries.
+    async fn renew_delegation(
+        &self,
+        _ctrl: BaseController,
+        request: RenewDelegationRequest,
+    ) -> Result<RenewDelegationResponse, rpc_types::error::Error> {
+        let lifetime = if request.lease_time == 0 {
+            DEFAULT_LEASE_LIFETIME
+        } else {
+            Duration::from_secs(request.lease_time as u64)
+        };
+        let mut renewed = false;
+        for entry in self.alloc.allocators.iter() {
+            if entry
+                .value()
+                .renew(request.requester_peer_id, lifetime)
+                .is_some()
+            {
+                renewed = true;
+            }
+        }
+        if !renewed {
+            return Ok(RenewDelegationResponse {
+                error: "no active lease for peer".to_string(),
+                granted_lifetime: 0,
+                server_unix_time: Self::now_unix(),
+            });
+        }
+        Self::persist_leases(&self.alloc.allocators);
+        Ok(RenewDelegationResponse {
+            error: String::new(),
+            granted_lifetime: lifetime.as_secs() as u32,
+            server_unix_time: Self::now_unix(),
+        })
+    }
+
+    /// Releases a peer's delegation immediately (clean shutdown path), tearing
+    /// down whatever OS-level state was installed for it.
+    async fn release_delegation(
+        &self,
+        _ctrl: BaseController,
+        request: ReleaseDelegationRequest,
+    ) -> Result<ReleaseDelegationResponse, rpc_types::error::Error> {
+        let tun = self.get_tun_ifname();
+        for entry in self.alloc.allocators.iter() {
+            let (_, unit_prefix_len) = *entry.key();
+            let Some(lease) = entry.value().release(request.requester_peer_id) else {
+                continue;
+            };
+            Self::reclaim_lease(
+                &self.global_ctx,
+                &self.alloc.onlink,
+                &self.server_overlay_addrs,
+                tun.as_deref(),
+                &lease,
+                unit_prefix_len,
+            )
+            .await;
+        }
+        Self::persist_leases(&self.alloc.allocators);
+        Ok(ReleaseDelegationResponse {
+            error: String::new(),
+        })
+    }
+}
+
+/// Fixed table-id range EasyTier uses for per-delegated-address policy
+/// routing, keyed off the address so it's stable across restarts.
+fn source_policy_table_id(addr: Ipv6Addr) -> u32 {
+    50000u32 + (u16::from_be_bytes(addr.segments()[7].to_be_bytes()) as u32 % 4096)
 }
 
+/// Routes traffic sourced from a just-delegated address out through this
+/// node's tun, via a dedicated policy-routing table keyed off the address.
+/// Takes the server's `OnlinkEnumerator` (rather than instantiating its own)
+/// so it shares the exact same tun-resolution logic as the rest of
+/// `Ipv6DelegateServer` -- a test-time stub enumerator applies here too.
 pub async fn configure_source_policy_for_addr(
     _global_ctx: &ArcGlobalCtx,
+    _onlink: &Arc<dyn OnlinkEnumerator>,
     _addr: Ipv6Addr,
 ) -> Result<(), Error> {
-    // Linux only for now
     #[cfg(target_os = "linux")]
-    {
-        let tun = {
-            use nix::ifaddrs::getifaddrs;
-            let mut found: Option<String> = None;
-            if let Some(ipv4) = _global_ctx.get_ipv4().map(|x| x.address()) {
-                if let Ok(addrs) = getifaddrs() {
-                    for iface in addrs {
-                        if let Some(a) = iface.address {
-                            if a.family() == Some(nix::sys::socket::AddressFamily::Inet) {
-                                let ip = a.as_sockaddr_in().unwrap().ip();
-                                if ip == ipv4 {
-                                    found = Some(iface.interface_name);
-                                    break;
-                                }
-                            }
-                        }
-                    }
-                }
-            }
-            found
-        };
-        if let Some(tun) = tun {
-            // Use a fixed table id range for EasyTier
-            let table_id =
-                50000u32 + (u16::from_be_bytes(_addr.segments()[7].to_be_bytes()) as u32 % 4096);
-            let rule_del = format!(
-                "ip -6 rule del from {}/128 table {} 2>/dev/null || true",
-                _addr, table_id
-            );
-            let rule_add = format!(
-                "ip -6 rule add from {}/128 table {} priority 1000",
-                _addr, table_id
-            );
-            let route = format!("ip -6 route replace default dev {} table {}", tun, table_id);
-            let _ = std::process::Command::new("sh")
-                .arg("-c")
-                .arg(rule_del)
-                .status();
-            let _ = std::process::Command::new("sh")
-                .arg("-c")
-                .arg(route)
-                .status();
-            let _ = std::process::Command::new("sh")
-                .arg("-c")
-                .arg(rule_add)
-                .status();
-        }
+    if let Some(tun) = Ipv6DelegateServer::resolve_tun_ifname(_global_ctx, _onlink) {
+        let table_id = source_policy_table_id(_addr);
+        let mut nl = netlink6::Handle::new()?;
+        let tun_idx = nl.ifindex(&tun)?;
+        // replace semantics: clear any stale rule for this address first.
+        let _ = nl.rule_from(_addr, table_id, 1000, false);
+        nl.default_route(tun_idx, table_id, true)?;
+        nl.rule_from(_addr, table_id, 1000, true)?;
+    }
+    Ok(())
+}
+
+/// Reverses `configure_source_policy_for_addr`, removing exactly the rule and
+/// table route it installed.
+pub async fn teardown_source_policy_for_addr(
+    _global_ctx: &ArcGlobalCtx,
+    _onlink: &Arc<dyn OnlinkEnumerator>,
+    _addr: Ipv6Addr,
+) -> Result<(), Error> {
+    #[cfg(target_os = "linux")]
+    if let Some(tun) = Ipv6DelegateServer::resolve_tun_ifname(_global_ctx, _onlink) {
+        let table_id = source_policy_table_id(_addr);
+        let mut nl = netlink6::Handle::new()?;
+        let tun_idx = nl.ifindex(&tun)?;
+        nl.rule_from(_addr, table_id, 1000, false)?;
+        nl.default_route(tun_idx, table_id, false)?;
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    /// A fixed, in-memory stand-in for `IfAddrsEnumerator` so
+    /// `DelegationAllocator`'s filtering and allocation-mode selection can be
+    /// exercised without touching real interfaces.
+    struct StubEnumerator(Vec<(String, IpAddr, IpAddr)>);
+
+    impl OnlinkEnumerator for StubEnumerator {
+        fn enumerate(&self) -> Vec<(String, IpAddr, IpAddr)> {
+            self.0.clone()
+        }
+    }
+
+    fn v6(s: &str) -> IpAddr {
+        IpAddr::V6(Ipv6Addr::from_str(s).unwrap())
+    }
+
+    #[test]
+    fn list_onlink_prefixes_filters_tunnels_and_non_global_addrs() {
+        let stub = StubEnumerator(vec![
+            // A loopback/tunnel-like name: filtered regardless of address.
+            (
+                "tun0".to_string(),
+                v6("fd00:1::1"),
+                v6("ffff:ffff:ffff:ffff::"),
+            ),
+            // Link-local: not eligible for delegation.
+            (
+                "eth0".to_string(),
+                v6("fe80::1"),
+                v6("ffff:ffff:ffff:ffff::"),
+            ),
+            // A real /64 uplink: should be kept.
+            (
+                "eth0".to_string(),
+                v6("2001:db8:1::1"),
+                v6("ffff:ffff:ffff:ffff::"),
+            ),
+            // A shorter, delegatable /56 block: also kept.
+            (
+                "eth1".to_string(),
+                v6("2001:db8:2::1"),
+                v6("ffff:ffff:ffff:ff00::"),
+            ),
+        ]);
+        let alloc = DelegationAllocator::new(Arc::new(stub));
+        let prefixes = alloc.list_onlink_prefixes();
+        assert_eq!(prefixes.len(), 2);
+        assert!(prefixes
+            .iter()
+            .any(|(name, pfx)| name == "eth0" && pfx.network_length() == 64));
+        assert!(prefixes
+            .iter()
+            .any(|(name, pfx)| name == "eth1" && pfx.network_length() == 56));
+    }
+
+    #[test]
+    fn alloc_prefix_for_peer_prefers_shorter_on_link_block() {
+        // Mirrors request_delegation's PD-vs-/128 branch: a peer asking for
+        // a /64 sub-prefix should get one carved from the /56, not fall back
+        // to the /128 NDP-proxy path.
+        let stub = StubEnumerator(vec![(
+            "eth1".to_string(),
+            v6("2001:db8:2::"),
+            v6("ffff:ffff:ffff:ff00::"),
+        )]);
+        let alloc = DelegationAllocator::new(Arc::new(stub));
+        let (iface, prefix) = alloc
+            .alloc_prefix_for_peer(1, 64, Duration::from_secs(60))
+            .expect("a /64 should be carvable out of the on-link /56");
+        assert_eq!(iface, "eth1");
+        assert_eq!(prefix.network_length(), 64);
+    }
+
+    #[test]
+    fn alloc_prefix_for_peer_falls_back_to_none_without_a_shorter_block() {
+        // Only a /64 on-link: no prefix-delegation block is available, so
+        // request_delegation should fall through to alloc_addrs_for_peer.
+        let stub = StubEnumerator(vec![(
+            "eth0".to_string(),
+            v6("2001:db8:1::"),
+            v6("ffff:ffff:ffff:ffff::"),
+        )]);
+        let alloc = DelegationAllocator::new(Arc::new(stub));
+        assert!(alloc
+            .alloc_prefix_for_peer(1, 64, Duration::from_secs(60))
+            .is_none());
+        let addrs = alloc.alloc_addrs_for_peer(1, 0, Duration::from_secs(60));
+        assert_eq!(addrs.len(), 1);
+        assert_eq!(addrs[0].1.network_length(), 128);
+    }
+}